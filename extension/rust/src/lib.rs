@@ -1,10 +1,13 @@
 
 use std::{
     collections::VecDeque,
+    convert::TryInto,
     mem::size_of,
     ptr::null_mut,
 };
 
+use hdrhistogram::Histogram;
+
 use ts_extend::{
     datum::ToDatum,
     elog,
@@ -26,6 +29,30 @@ use ts_extend::{
 type Seconds = i64;
 const USECS_PER_SEC: i64 = 1_000_000;
 
+// values are scaled into this many fixed-point "ticks" before being recorded
+// in the histogram, which only tracks non-negative integers, then scaled back
+// down when a quantile is read out.
+const HISTOGRAM_SCALE: f64 = 1_000_000.0;
+const HISTOGRAM_SIGFIGS: u8 = 3;
+const HISTOGRAM_MAX_TICKS: u64 = u64::MAX / 2;
+
+// an output this large is already a bogus call (at 8 bytes per bucket that's
+// already an 8 MB array); better to fail loudly here than to hand
+// `Vec::with_capacity` an unchecked, possibly enormous derived count.
+const MAX_EXPECTED_BUCKETS: usize = 1_000_000;
+
+// Shared by every `add_data_point` below: returns the index at which `time`
+// should be inserted into `points` to keep it sorted latest-first. Rows
+// normally arrive in descending time order already, so the common case is
+// that `time` belongs at the back -- checking that first keeps that path
+// O(1) instead of always scanning (or binary-searching) the whole buffer.
+fn sorted_insert_idx(points: &VecDeque<(TimestampTz, f64)>, time: TimestampTz) -> usize {
+    match points.back() {
+        Some(&(oldest_time, _)) if time <= oldest_time => points.len(),
+        _ => points.iter().position(|&(t, _)| t <= time).unwrap_or(points.len()),
+    }
+}
+
 pg_fn!{
     // prom divides time into no-sliding windows of fixed size, e.g.
     // |  5 seconds  |  5 seconds  |  5 seconds  |  5 seconds  |  5 seconds  |
@@ -39,6 +66,11 @@ pg_fn!{
         greatest_time: TimestampTz,
         step_size: Seconds, // `prev_now - step` is where the next window starts
         window_size: Seconds, // the size of a window to delta over
+        // how far back (from the newest point seen so far) raw points are kept
+        // around; `NULL` means no limit. Bounds memory use for dense series or
+        // heavily-overlapping windows, at the cost of correctness for rows that
+        // arrive more out of order than this.
+        max_buffered_duration: Option<Seconds>,
         time: TimestampTz,
         val: f64;
         fcinfo
@@ -57,8 +89,9 @@ pg_fn!{
             in_context(agg_ctx, || {
                 let state = state.map(|s| &mut *s).unwrap_or_else(|| {
                     let expected_deltas = ((greatest_time - lowest_time) / (step_size * USECS_PER_SEC)) + 1;
-                    let state = GapfillDeltaTransition::new(expected_deltas as _, greatest_time, window_size, step_size)
-                        .into();
+                    let state = GapfillDeltaTransition::new(
+                        expected_deltas as _, greatest_time, window_size, step_size, max_buffered_duration,
+                    ).into();
                     Box::leak(state)
                 });
 
@@ -90,60 +123,752 @@ pg_fn!{
 }
 
 struct GapfillDeltaTransition {
-    window: VecDeque<(TimestampTz, f64)>,
+    // buffered points, kept in descending-timestamp order regardless of the
+    // order they arrived in; see `add_data_point`.
+    points: VecDeque<(TimestampTz, f64)>,
     deltas: Vec<Datum>,
     nulls: Vec<bool>,
-    current_window_max: TimestampTz,
-    current_window_min: TimestampTz,
+    greatest_time: TimestampTz,
+    window_size: TimestampTz,
     step_size: TimestampTz,
+    max_buffered_duration: Option<TimestampTz>,
 }
 
 impl GapfillDeltaTransition {
-    pub fn new(expected_deltas: usize, greatest_time: TimestampTz, window_size: Seconds, step_size: Seconds)
-    -> Self {
+    pub fn new(
+        expected_deltas: usize,
+        greatest_time: TimestampTz,
+        window_size: Seconds,
+        step_size: Seconds,
+        max_buffered_duration: Option<Seconds>,
+    ) -> Self {
+        if expected_deltas > MAX_EXPECTED_BUCKETS {
+            elog!(Error, "gapfill_delta output of {} buckets exceeds the maximum of {}", expected_deltas, MAX_EXPECTED_BUCKETS)
+        }
+
         GapfillDeltaTransition{
+            points: VecDeque::default(),
+            deltas: Vec::with_capacity(expected_deltas),
+            nulls: Vec::with_capacity(expected_deltas),
+            greatest_time,
+            window_size: window_size*USECS_PER_SEC,
+            step_size: step_size*USECS_PER_SEC,
+            max_buffered_duration: max_buffered_duration.map(|d| d*USECS_PER_SEC),
+        }
+    }
+
+    // Rows can arrive out of order (a common occurrence when backfilling or
+    // merging chunks), so instead of assuming descending arrival order and
+    // flushing windows as we go, every point is inserted into its correct
+    // position in a timestamp-sorted `VecDeque` and the windows are only
+    // computed once, by walking that sorted buffer (see `flush_all`). A
+    // single out-of-order row then just lands at its correct spot instead of
+    // silently flushing the wrong bucket.
+    //
+    // If `max_buffered_duration` is set, points older than that relative to
+    // the newest point seen so far are evicted, bounding how much raw data a
+    // dense or heavily-overlapping-window series can pile up in memory; rows
+    // that arrive more out of order than the configured duration are lost
+    // rather than retained indefinitely.
+    pub fn add_data_point(&mut self, time: TimestampTz, val: f64) {
+        self.points.insert(sorted_insert_idx(&self.points, time), (time, val));
+        self.evict_stale();
+    }
+
+    // Drops points older than `max_buffered_duration` relative to the newest
+    // point currently buffered. Called after every insertion and after
+    // `combine` merges two partial states, since a combine-tree over N
+    // workers that only concatenated would let up to N times the configured
+    // bound accumulate before this ever ran.
+    fn evict_stale(&mut self) {
+        let max_buffered_duration = match self.max_buffered_duration {
+            Some(d) => d,
+            None => return,
+        };
+        let newest_time = match self.points.front() {
+            Some(&(t, _)) => t,
+            None => return,
+        };
+        while let Some(&(oldest_time, _)) = self.points.back() {
+            if newest_time - oldest_time <= max_buffered_duration {
+                break
+            }
+            self.points.pop_back();
+        }
+    }
+
+    // Walks the buffered points -- sorted latest-first -- one window at a
+    // time, taking `latest - earliest` for each exactly as the original
+    // incremental flush did, but now driven by the sorted buffer rather than
+    // by arrival order. Stops once the buffer is drained; trailing empty
+    // windows below the last real data point are not emitted, matching the
+    // prior incremental behavior.
+    fn flush_all(&mut self) {
+        let mut window_min = self.greatest_time - self.window_size;
+
+        while !self.points.is_empty() {
+            let mut count = 0u32;
+            let mut latest_val = 0.0;
+            let mut earliest_val = 0.0;
+
+            while let Some(&(time, val)) = self.points.front() {
+                if time <= window_min {
+                    break
+                }
+                if count == 0 {
+                    latest_val = val;
+                }
+                earliest_val = val;
+                count += 1;
+                self.points.pop_front();
+            }
+
+            // if there are 1 or fewer values in the window, store NULL
+            if count >= 2 {
+                self.deltas.push((latest_val - earliest_val).to_datum());
+                self.nulls.push(false);
+            } else {
+                self.nulls.push(true);
+            }
+
+            window_min -= self.step_size;
+        }
+    }
+
+    pub fn to_pg_array(&mut self) -> *mut ArrayType{
+        self.flush_all();
+        unsafe {
+            construct_md_array(
+                self.deltas.as_mut_ptr(),
+                self.nulls.as_mut_ptr(),
+                1,
+                &mut (self.deltas.len() as _),
+                &mut 1,
+                FLOAT8OID,
+                size_of::<f64>() as _,
+                FLOAT8PASSBYVAL != 0,
+                'd' as u8 as _,
+            )
+        }
+    }
+
+    // Flushing is deferred to `to_pg_array`, so at the point a transition
+    // state crosses a parallel-worker boundary it is always still just the
+    // raw, sorted, not-yet-windowed points -- nothing to do here but write
+    // those out alongside the query-level parameters needed to window them.
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.greatest_time.to_le_bytes());
+        buf.extend_from_slice(&self.window_size.to_le_bytes());
+        buf.extend_from_slice(&self.step_size.to_le_bytes());
+        // -1 is used as the "no limit" sentinel since a real duration is always positive.
+        buf.extend_from_slice(&self.max_buffered_duration.unwrap_or(-1).to_le_bytes());
+
+        buf.extend_from_slice(&(self.points.len() as u32).to_le_bytes());
+        for &(time, val) in &self.points {
+            buf.extend_from_slice(&time.to_le_bytes());
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+
+        buf
+    }
+
+    fn deserialize(bytes: &[u8]) -> Self {
+        let mut cursor = 0;
+        macro_rules! read {
+            ($ty:ty) => {{
+                let size = size_of::<$ty>();
+                let val = <$ty>::from_le_bytes(bytes[cursor..cursor + size].try_into().unwrap());
+                cursor += size;
+                val
+            }};
+        }
+
+        let greatest_time: TimestampTz = read!(TimestampTz);
+        let window_size: TimestampTz = read!(TimestampTz);
+        let step_size: TimestampTz = read!(TimestampTz);
+        let max_buffered_duration = match read!(TimestampTz) {
+            -1 => None,
+            d => Some(d),
+        };
+
+        let num_points = read!(u32) as usize;
+        let mut points = VecDeque::with_capacity(num_points);
+        for _ in 0..num_points {
+            let time: TimestampTz = read!(TimestampTz);
+            let val: f64 = read!(f64);
+            points.push_back((time, val));
+        }
+
+        GapfillDeltaTransition{
+            points, deltas: Vec::new(), nulls: Vec::new(), greatest_time, window_size, step_size, max_buffered_duration,
+        }
+    }
+
+    // Merges two partial per-worker transitions produced by parallel workers
+    // scanning disjoint ranges of the same logical aggregate call (e.g.
+    // different hypertable chunks). Neither side has windowed its points yet
+    // (that only happens once, in `to_pg_array`), so combining is just a
+    // k-way-style ordered merge of the two timestamp-sorted point buffers,
+    // followed by re-running the `max_buffered_duration` eviction against the
+    // merged buffer: each side already evicted to its own bound independently,
+    // but a combine-tree over N workers would otherwise let up to N times that
+    // bound pile up, making the result depend on how the aggregate happened to
+    // be parallelized.
+    fn combine(a: &GapfillDeltaTransition, b: &GapfillDeltaTransition) -> GapfillDeltaTransition {
+        if a.greatest_time != b.greatest_time || a.window_size != b.window_size || a.step_size != b.step_size {
+            elog!(Error, "cannot combine gapfill_delta states from different windowing parameters")
+        }
+
+        let mut points = VecDeque::with_capacity(a.points.len() + b.points.len());
+        let mut a_iter = a.points.iter().peekable();
+        let mut b_iter = b.points.iter().peekable();
+        loop {
+            let next = match (a_iter.peek(), b_iter.peek()) {
+                (Some(&&a_point), Some(&&b_point)) => {
+                    if a_point.0 >= b_point.0 { a_iter.next(); a_point } else { b_iter.next(); b_point }
+                },
+                (Some(&&a_point), None) => { a_iter.next(); a_point },
+                (None, Some(&&b_point)) => { b_iter.next(); b_point },
+                (None, None) => break,
+            };
+            points.push_back(next);
+        }
+
+        let mut combined = GapfillDeltaTransition{
+            points,
+            deltas: Vec::new(),
+            nulls: Vec::new(),
+            greatest_time: a.greatest_time,
+            window_size: a.window_size,
+            step_size: a.step_size,
+            max_buffered_duration: a.max_buffered_duration.or(b.max_buffered_duration),
+        };
+        combined.evict_stale();
+        combined
+    }
+}
+
+// float8 is pass-by-value, so a `Datum` produced by `f64::to_datum` is just the
+// f64's bit pattern; this is the inverse of that conversion.
+fn datum_as_f64(datum: Datum) -> f64 {
+    unsafe { std::mem::transmute(datum) }
+}
+
+pg_fn!{
+    pub fn gapfill_delta_serialize(
+        state: Option<*mut GapfillDeltaTransition>;
+        fcinfo
+    ) -> Option<Vec<u8>> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_delta_serialize as an aggregate")
+        }
+
+        unsafe {
+            state.map(|s| (&*s).serialize())
+        }
+    }
+}
+
+pg_fn!{
+    pub fn gapfill_delta_deserialize(
+        bytes: Vec<u8>;
+        fcinfo
+    ) -> Option<*mut GapfillDeltaTransition> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_delta_deserialize as an aggregate")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                let state = GapfillDeltaTransition::deserialize(&bytes).into();
+                Some(Box::leak(state) as *mut GapfillDeltaTransition)
+            })
+        }
+    }
+}
+
+pg_fn!{
+    pub fn gapfill_delta_combine(
+        state1: Option<*mut GapfillDeltaTransition>,
+        state2: Option<*mut GapfillDeltaTransition>;
+        fcinfo
+    ) -> Option<*mut GapfillDeltaTransition> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_delta_combine as an aggregate")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                match (state1, state2) {
+                    (None, None) => None,
+                    (Some(s), None) | (None, Some(s)) => Some(s),
+                    (Some(a), Some(b)) => {
+                        let merged = GapfillDeltaTransition::combine(&*a, &*b).into();
+                        Some(Box::leak(merged) as *mut GapfillDeltaTransition)
+                    },
+                }
+            })
+        }
+    }
+}
+
+pg_fn!{
+    // `gapfill_rate`/`gapfill_increase` share this transition function: unlike
+    // `gapfill_delta_transition`, which takes `last - first`, this one walks the
+    // buffered window in time order, undoes counter resets, and extrapolates the
+    // delta to the edges of the window the way PromQL's `rate`/`increase` do.
+    pub fn gapfill_rate_transition(
+        state: Option<*mut GapfillRateTransition>,
+        lowest_time: TimestampTz,
+        greatest_time: TimestampTz,
+        step_size: Seconds, // `prev_now - step` is where the next window starts
+        window_size: Seconds, // the size of a window to delta over
+        // how far back (from the newest point seen so far) raw points are kept
+        // around; `NULL` means no limit. See `GapfillDeltaTransition`.
+        max_buffered_duration: Option<Seconds>,
+        time: TimestampTz,
+        val: f64;
+        fcinfo
+    ) -> Option<*mut GapfillRateTransition> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_rate_transition as an aggregate")
+        }
+
+        if time <= lowest_time || time > greatest_time {
+            elog!(Error, "input time less than lowest time")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                let state = state.map(|s| &mut *s).unwrap_or_else(|| {
+                    let expected_deltas = ((greatest_time - lowest_time) / (step_size * USECS_PER_SEC)) + 1;
+                    let state = GapfillRateTransition::new(
+                        expected_deltas as _, greatest_time, window_size, step_size, max_buffered_duration,
+                    ).into();
+                    Box::leak(state)
+                });
+
+                state.add_data_point(time, val);
+
+                Some(state as *mut GapfillRateTransition)
+            })
+        }
+    }
+}
+
+pg_fn!{
+    pub fn gapfill_increase_final(
+        state: Option<*mut GapfillRateTransition>;
+        fcinfo
+    ) -> Option<*mut ArrayType> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_rate_transition as an aggregate")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                state.map(|s| (&mut *s).to_pg_array(None))
+            })
+        }
+    }
+}
+
+pg_fn!{
+    pub fn gapfill_rate_final(
+        state: Option<*mut GapfillRateTransition>;
+        fcinfo
+    ) -> Option<*mut ArrayType> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_rate_transition as an aggregate")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                state.map(|s| {
+                    let window_size = (&mut *s).window_size;
+                    (&mut *s).to_pg_array(Some(window_size))
+                })
+            })
+        }
+    }
+}
+
+struct GapfillRateTransition {
+    // buffered points, kept in descending-timestamp order regardless of the
+    // order they arrived in; see `add_data_point` on `GapfillDeltaTransition`.
+    window: VecDeque<(TimestampTz, f64)>,
+    deltas: Vec<f64>,
+    nulls: Vec<bool>,
+    greatest_time: TimestampTz,
+    window_size_usecs: TimestampTz,
+    step_size: TimestampTz,
+    window_size: Seconds,
+    max_buffered_duration: Option<TimestampTz>,
+}
+
+impl GapfillRateTransition {
+    pub fn new(
+        expected_deltas: usize,
+        greatest_time: TimestampTz,
+        window_size: Seconds,
+        step_size: Seconds,
+        max_buffered_duration: Option<Seconds>,
+    ) -> Self {
+        if expected_deltas > MAX_EXPECTED_BUCKETS {
+            elog!(Error, "gapfill_rate output of {} buckets exceeds the maximum of {}", expected_deltas, MAX_EXPECTED_BUCKETS)
+        }
+
+        GapfillRateTransition{
             window: VecDeque::default(),
             deltas: Vec::with_capacity(expected_deltas),
             nulls: Vec::with_capacity(expected_deltas),
-            current_window_max: greatest_time,
-            current_window_min: greatest_time - window_size*USECS_PER_SEC,
+            greatest_time,
+            window_size_usecs: window_size*USECS_PER_SEC,
             step_size: step_size*USECS_PER_SEC,
+            window_size,
+            max_buffered_duration: max_buffered_duration.map(|d| d*USECS_PER_SEC),
         }
     }
 
+    // Rows can arrive out of order (see `GapfillDeltaTransition::add_data_point`),
+    // so points are inserted into their correct sorted position instead of being
+    // pushed to the back, and windowing is deferred to a single pass over the
+    // sorted buffer in `flush_all`. If `max_buffered_duration` is set, points
+    // older than that relative to the newest point seen so far are evicted,
+    // same as `GapfillDeltaTransition::evict_stale`.
     pub fn add_data_point(&mut self, time: TimestampTz, val: f64) {
-        while !self.in_current_window(time) {
-            self.flush_current_window()
+        self.window.insert(sorted_insert_idx(&self.window, time), (time, val));
+        self.evict_stale();
+    }
+
+    fn evict_stale(&mut self) {
+        let max_buffered_duration = match self.max_buffered_duration {
+            Some(d) => d,
+            None => return,
+        };
+        let newest_time = match self.window.front() {
+            Some(&(t, _)) => t,
+            None => return,
+        };
+        while let Some(&(oldest_time, _)) = self.window.back() {
+            if newest_time - oldest_time <= max_buffered_duration {
+                break
+            }
+            self.window.pop_back();
         }
+    }
+
+    // Walks the buffered points one window at a time, extrapolating each
+    // window's corrected delta the way `extrapolated_delta` always did, but
+    // now driven by the sorted buffer rather than by arrival order.
+    fn flush_all(&mut self) {
+        let mut window_min = self.greatest_time - self.window_size_usecs;
+        let mut window_max = self.greatest_time;
+
+        while !self.window.is_empty() {
+            let mut bucket = Vec::new();
+            while let Some(&(time, _)) = self.window.front() {
+                if time <= window_min {
+                    break
+                }
+                bucket.push(self.window.pop_front().unwrap());
+            }
 
-        self.window.push_back((time, val))
+            match Self::extrapolated_delta(&bucket, window_min, window_max) {
+                Some(delta) => {
+                    self.deltas.push(delta);
+                    self.nulls.push(false);
+                },
+                // if there are fewer than 2 values in the window, store NULL
+                None => self.nulls.push(true),
+            }
+
+            window_min -= self.step_size;
+            window_max -= self.step_size;
+        }
     }
 
-    fn in_current_window(&self, time: TimestampTz) -> bool {
-        time > self.current_window_min
+    // walks the window from earliest to latest (the window is buffered in
+    // descending timestamp order, so we iterate back-to-front), undoing counter
+    // resets as we go, then extrapolates the corrected delta out to the edges
+    // of the window the way PromQL's `extrapolatedRate` does.
+    fn extrapolated_delta(window: &[(TimestampTz, f64)], window_min: TimestampTz, window_max: TimestampTz)
+    -> Option<f64> {
+        if window.len() < 2 {
+            return None
+        }
+
+        let mut iter = window.iter().rev();
+        let &(first_ts, first_val) = iter.next().unwrap();
+
+        let mut correction = 0f64;
+        let mut prev_val = first_val;
+        let mut last_ts = first_ts;
+        let mut last_val = first_val;
+        let mut num_samples = 1u32;
+
+        for &(time, val) in iter {
+            if val < prev_val {
+                correction += prev_val;
+            }
+            prev_val = val;
+            last_ts = time;
+            last_val = val;
+            num_samples += 1;
+        }
+
+        let raw_delta = (last_val + correction) - first_val;
+
+        let sampled_interval = (last_ts - first_ts) as f64 / USECS_PER_SEC as f64;
+        if sampled_interval <= 0.0 {
+            return Some(raw_delta)
+        }
+        let avg_interval = sampled_interval / (num_samples - 1) as f64;
+
+        let mut to_start = (first_ts - window_min) as f64 / USECS_PER_SEC as f64;
+        let to_end = (window_max - last_ts) as f64 / USECS_PER_SEC as f64;
+
+        // counters can't have been negative just before the window started, so
+        // don't extrapolate further back than that would imply
+        if raw_delta > 0.0 && first_val >= 0.0 {
+            let to_zero = sampled_interval * (first_val / raw_delta);
+            if to_zero < to_start {
+                to_start = to_zero;
+            }
+        }
+
+        let extrapolation_threshold = avg_interval / 2.0;
+        let capped_to_start = to_start.min(extrapolation_threshold);
+        let capped_to_end = to_end.min(extrapolation_threshold);
+
+        let extrapolated_interval = sampled_interval + capped_to_start + capped_to_end;
+        Some(raw_delta * (extrapolated_interval / sampled_interval))
     }
 
-    fn flush_current_window(&mut self) {
-        match (self.window.front(), self.window.back()) {
-            (Some((_, latest_val)), Some((_, earliest_val))) => {
-                self.deltas.push((latest_val - earliest_val).to_datum());
-                self.nulls.push(false);
-            },
-            // if there are 1 or fewer values in the window, store NULL
-            (_, _) => self.nulls.push(true),
+    // `divisor` is `Some(window_size)` for `gapfill_rate` (which reports a
+    // per-second rate) and `None` for `gapfill_increase` (which reports the
+    // raw extrapolated delta).
+    pub fn to_pg_array(&mut self, divisor: Option<Seconds>) -> *mut ArrayType {
+        self.flush_all();
+
+        let mut deltas: Vec<Datum> = self.deltas.iter().map(|delta| {
+            match divisor {
+                Some(window_size) => (delta / window_size as f64).to_datum(),
+                None => delta.to_datum(),
+            }
+        }).collect();
+
+        unsafe {
+            construct_md_array(
+                deltas.as_mut_ptr(),
+                self.nulls.as_mut_ptr(),
+                1,
+                &mut (deltas.len() as _),
+                &mut 1,
+                FLOAT8OID,
+                size_of::<f64>() as _,
+                FLOAT8PASSBYVAL != 0,
+                'd' as u8 as _,
+            )
+        }
+    }
+}
+
+
+pg_fn!{
+    // same windowing as `gapfill_delta_transition`, but instead of keeping only
+    // the first/last value in the window, every in-window value is fed into a
+    // bounded histogram so we can report the `quantile`-th percentile of the
+    // window without keeping every sample around.
+    pub fn gapfill_quantile_transition(
+        state: Option<*mut GapfillQuantileTransition>,
+        lowest_time: TimestampTz,
+        greatest_time: TimestampTz,
+        step_size: Seconds, // `prev_now - step` is where the next window starts
+        window_size: Seconds, // the size of a window to delta over
+        // how far back (from the newest point seen so far) raw points are kept
+        // around; `NULL` means no limit. See `GapfillDeltaTransition`.
+        max_buffered_duration: Option<Seconds>,
+        quantile: f64,
+        time: TimestampTz,
+        val: f64;
+        fcinfo
+    ) -> Option<*mut GapfillQuantileTransition> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_quantile_transition as an aggregate")
+        }
+
+        if time <= lowest_time || time > greatest_time {
+            elog!(Error, "input time less than lowest time")
+        }
+
+        if quantile < 0.0 || quantile > 1.0 {
+            elog!(Error, "quantile must be between 0.0 and 1.0")
+        }
+
+        if val < 0.0 {
+            elog!(Error, "gapfill_quantile does not support negative values")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                let state = state.map(|s| &mut *s).unwrap_or_else(|| {
+                    let expected_deltas = ((greatest_time - lowest_time) / (step_size * USECS_PER_SEC)) + 1;
+                    let state = GapfillQuantileTransition::new(
+                        expected_deltas as _, greatest_time, window_size, step_size, quantile, max_buffered_duration,
+                    ).into();
+                    Box::leak(state)
+                });
+
+                state.add_data_point(time, val);
+
+                Some(state as *mut GapfillQuantileTransition)
+            })
+        }
+    }
+}
+
+pg_fn!{
+    pub fn gapfill_quantile_final(
+        state: Option<*mut GapfillQuantileTransition>;
+        fcinfo
+    ) -> Option<*mut ArrayType> {
+        let mut agg_ctx: MemoryContext = null_mut();
+
+        if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
+            elog!(Error, "must call gapfill_quantile_transition as an aggregate")
+        }
+
+        unsafe {
+            in_context(agg_ctx, || {
+                state.map(|s| (&mut *s).to_pg_array())
+            })
+        }
+    }
+}
+
+struct GapfillQuantileTransition {
+    // buffered points, kept in descending-timestamp order regardless of the
+    // order they arrived in; see `add_data_point` on `GapfillDeltaTransition`.
+    points: VecDeque<(TimestampTz, f64)>,
+    histogram: Histogram<u64>,
+    quantile: f64,
+    deltas: Vec<Datum>,
+    nulls: Vec<bool>,
+    greatest_time: TimestampTz,
+    window_size: TimestampTz,
+    step_size: TimestampTz,
+    max_buffered_duration: Option<TimestampTz>,
+}
+
+impl GapfillQuantileTransition {
+    pub fn new(
+        expected_deltas: usize,
+        greatest_time: TimestampTz,
+        window_size: Seconds,
+        step_size: Seconds,
+        quantile: f64,
+        max_buffered_duration: Option<Seconds>,
+    ) -> Self {
+        if expected_deltas > MAX_EXPECTED_BUCKETS {
+            elog!(Error, "gapfill_quantile output of {} buckets exceeds the maximum of {}", expected_deltas, MAX_EXPECTED_BUCKETS)
+        }
+
+        GapfillQuantileTransition{
+            points: VecDeque::default(),
+            histogram: Histogram::new_with_bounds(1, HISTOGRAM_MAX_TICKS, HISTOGRAM_SIGFIGS)
+                .unwrap_or_else(|e| elog!(Error, "could not create histogram: {}", e)),
+            quantile,
+            deltas: Vec::with_capacity(expected_deltas),
+            nulls: Vec::with_capacity(expected_deltas),
+            greatest_time,
+            window_size: window_size*USECS_PER_SEC,
+            step_size: step_size*USECS_PER_SEC,
+            max_buffered_duration: max_buffered_duration.map(|d| d*USECS_PER_SEC),
         }
+    }
 
-        self.current_window_min -= self.step_size;
-        self.current_window_max -= self.step_size;
+    // Rows can arrive out of order (see `GapfillDeltaTransition::add_data_point`),
+    // so points are inserted into their correct sorted position instead of being
+    // recorded into the histogram immediately, since which window a point falls
+    // into can only be known once its position relative to the others is fixed.
+    // The histogram is only fed from the sorted buffer, in `flush_all`. If
+    // `max_buffered_duration` is set, points older than that relative to the
+    // newest point seen so far are evicted, same as
+    // `GapfillDeltaTransition::evict_stale`.
+    pub fn add_data_point(&mut self, time: TimestampTz, val: f64) {
+        self.points.insert(sorted_insert_idx(&self.points, time), (time, val));
+        self.evict_stale();
+    }
 
-        let current_window_max = self.current_window_max;
-        self.window.drain(..)
-            .take_while(|(time, _)| *time > current_window_max)
-            .for_each(|_|())
+    fn evict_stale(&mut self) {
+        let max_buffered_duration = match self.max_buffered_duration {
+            Some(d) => d,
+            None => return,
+        };
+        let newest_time = match self.points.front() {
+            Some(&(t, _)) => t,
+            None => return,
+        };
+        while let Some(&(oldest_time, _)) = self.points.back() {
+            if newest_time - oldest_time <= max_buffered_duration {
+                break
+            }
+            self.points.pop_back();
+        }
+    }
+
+    // Walks the buffered points one window at a time, feeding each into the
+    // histogram and reading the quantile back out, driven by the sorted
+    // buffer rather than by arrival order.
+    fn flush_all(&mut self) {
+        let mut window_min = self.greatest_time - self.window_size;
+
+        while !self.points.is_empty() {
+            while let Some(&(time, val)) = self.points.front() {
+                if time <= window_min {
+                    break
+                }
+                // negative values are rejected in gapfill_quantile_transition
+                // before they ever reach here; the clamp raises values that
+                // round to 0 ticks to 1, since the histogram can't record a
+                // tick below 1, and caps values above HISTOGRAM_MAX_TICKS.
+                let ticks = (val * HISTOGRAM_SCALE).round() as u64;
+                let ticks = ticks.clamp(1, HISTOGRAM_MAX_TICKS);
+                let _ = self.histogram.record(ticks);
+                self.points.pop_front();
+            }
+
+            if self.histogram.is_empty() {
+                self.nulls.push(true);
+            } else {
+                let ticks = self.histogram.value_at_quantile(self.quantile);
+                self.deltas.push((ticks as f64 / HISTOGRAM_SCALE).to_datum());
+                self.nulls.push(false);
+            }
+            self.histogram.reset();
+
+            window_min -= self.step_size;
+        }
     }
 
     pub fn to_pg_array(&mut self) -> *mut ArrayType{
-        self.flush_current_window();
+        self.flush_all();
         unsafe {
             construct_md_array(
                 self.deltas.as_mut_ptr(),
@@ -160,3 +885,290 @@ impl GapfillDeltaTransition {
     }
 }
 
+#[cfg(test)]
+mod gapfill_delta_tests {
+    use super::*;
+
+    fn build(points: &[(TimestampTz, f64)], greatest_time: TimestampTz, window_size: Seconds, step_size: Seconds)
+    -> GapfillDeltaTransition {
+        let mut state = GapfillDeltaTransition::new(points.len(), greatest_time, window_size, step_size, None);
+        for &(time, val) in points {
+            state.add_data_point(time, val);
+        }
+        state
+    }
+
+    fn collect_deltas(state: &mut GapfillDeltaTransition) -> Vec<Option<f64>> {
+        state.flush_all();
+        let mut deltas = state.deltas.iter();
+        state.nulls.iter()
+            .map(|&is_null| if is_null { None } else { Some(datum_as_f64(*deltas.next().unwrap())) })
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order_points_match_sorted_order() {
+        let greatest_time = 50 * USECS_PER_SEC;
+
+        let points = vec![
+            (48 * USECS_PER_SEC, 4.0),
+            (42 * USECS_PER_SEC, 1.0),
+            (35 * USECS_PER_SEC, 2.0),
+            (28 * USECS_PER_SEC, 1.0),
+            (22 * USECS_PER_SEC, 5.0),
+            (5 * USECS_PER_SEC, 9.0),
+        ];
+
+        let expected = collect_deltas(&mut build(&points, greatest_time, 10, 10));
+        assert_eq!(expected, vec![Some(3.0), None, Some(-4.0), None, None]);
+
+        let shuffles: [[usize; 6]; 4] = [
+            [2, 0, 4, 5, 1, 3],
+            [5, 4, 3, 2, 1, 0],
+            [0, 2, 4, 1, 3, 5],
+            [1, 3, 5, 0, 2, 4],
+        ];
+        for order in &shuffles {
+            let shuffled: Vec<_> = order.iter().map(|&i| points[i]).collect();
+            let actual = collect_deltas(&mut build(&shuffled, greatest_time, 10, 10));
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn single_sample_windows_are_null() {
+        let greatest_time = 20 * USECS_PER_SEC;
+        let points = vec![(15 * USECS_PER_SEC, 1.0)];
+        let actual = collect_deltas(&mut build(&points, greatest_time, 10, 10));
+        assert_eq!(actual, vec![None]);
+    }
+
+    #[test]
+    fn max_buffered_duration_evicts_points_older_than_the_newest_seen() {
+        let greatest_time = 100 * USECS_PER_SEC;
+        let mut state = GapfillDeltaTransition::new(10, greatest_time, 10, 10, Some(5));
+
+        state.add_data_point(90 * USECS_PER_SEC, 1.0);
+        state.add_data_point(80 * USECS_PER_SEC, 2.0); // 10s behind the newest point: evicted
+        assert_eq!(state.points.len(), 1);
+
+        state.add_data_point(88 * USECS_PER_SEC, 3.0); // 2s behind the newest point: kept
+        assert_eq!(state.points.len(), 2);
+    }
+
+    #[test]
+    fn combine_reapplies_max_buffered_duration_to_the_merged_buffer() {
+        let greatest_time = 100 * USECS_PER_SEC;
+
+        let mut a = GapfillDeltaTransition::new(10, greatest_time, 10, 10, Some(5));
+        a.add_data_point(90 * USECS_PER_SEC, 1.0);
+
+        let mut b = GapfillDeltaTransition::new(10, greatest_time, 10, 10, Some(5));
+        b.add_data_point(81 * USECS_PER_SEC, 2.0);
+
+        // each side is within its own 5s bound, but naively concatenating
+        // would leave both points (9s apart) buffered at once.
+        let combined = GapfillDeltaTransition::combine(&a, &b);
+        assert_eq!(combined.points.len(), 1);
+        assert_eq!(combined.points.front().unwrap().0, 90 * USECS_PER_SEC);
+    }
+
+    #[test]
+    fn serialize_deserialize_round_trip() {
+        let greatest_time = 50 * USECS_PER_SEC;
+        let points = vec![
+            (48 * USECS_PER_SEC, 4.0),
+            (35 * USECS_PER_SEC, 2.0),
+            (22 * USECS_PER_SEC, 5.0),
+        ];
+
+        let state = build(&points, greatest_time, 10, 10);
+        let bytes = state.serialize();
+        let round_tripped = GapfillDeltaTransition::deserialize(&bytes);
+
+        assert_eq!(round_tripped.greatest_time, state.greatest_time);
+        assert_eq!(round_tripped.window_size, state.window_size);
+        assert_eq!(round_tripped.step_size, state.step_size);
+        assert_eq!(round_tripped.max_buffered_duration, state.max_buffered_duration);
+        assert_eq!(round_tripped.points, state.points);
+    }
+
+    #[test]
+    fn serialize_deserialize_round_trip_preserves_max_buffered_duration() {
+        let greatest_time = 50 * USECS_PER_SEC;
+        let mut state = GapfillDeltaTransition::new(10, greatest_time, 10, 10, Some(5));
+        state.add_data_point(48 * USECS_PER_SEC, 4.0);
+
+        let bytes = state.serialize();
+        let round_tripped = GapfillDeltaTransition::deserialize(&bytes);
+        assert_eq!(round_tripped.max_buffered_duration, Some(5 * USECS_PER_SEC));
+    }
+
+    #[test]
+    fn combine_matches_the_equivalent_serial_result() {
+        let greatest_time = 50 * USECS_PER_SEC;
+
+        let points = vec![
+            (48 * USECS_PER_SEC, 4.0),
+            (42 * USECS_PER_SEC, 1.0),
+            (35 * USECS_PER_SEC, 2.0),
+            (28 * USECS_PER_SEC, 1.0),
+            (22 * USECS_PER_SEC, 5.0),
+            (5 * USECS_PER_SEC, 9.0),
+        ];
+
+        // a single worker scanning every point, serially
+        let serial = collect_deltas(&mut build(&points, greatest_time, 10, 10));
+
+        // two "workers" each scanning a disjoint half of the same points,
+        // combined into one state before windowing
+        let worker_a = build(&points[..3], greatest_time, 10, 10);
+        let worker_b = build(&points[3..], greatest_time, 10, 10);
+        let mut combined = GapfillDeltaTransition::combine(&worker_a, &worker_b);
+        let parallel = collect_deltas(&mut combined);
+
+        assert_eq!(parallel, serial);
+    }
+}
+
+#[cfg(test)]
+mod gapfill_rate_tests {
+    use super::*;
+
+    fn build(points: &[(TimestampTz, f64)], greatest_time: TimestampTz, window_size: Seconds, step_size: Seconds)
+    -> GapfillRateTransition {
+        let mut state = GapfillRateTransition::new(points.len(), greatest_time, window_size, step_size, None);
+        for &(time, val) in points {
+            state.add_data_point(time, val);
+        }
+        state
+    }
+
+    fn collect_deltas(state: &mut GapfillRateTransition) -> Vec<Option<f64>> {
+        state.flush_all();
+        let mut deltas = state.deltas.iter();
+        state.nulls.iter()
+            .map(|&is_null| if is_null { None } else { Some(*deltas.next().unwrap()) })
+            .collect()
+    }
+
+    #[test]
+    fn counter_reset_and_edge_extrapolation() {
+        let greatest_time = 30 * USECS_PER_SEC;
+        let points = vec![
+            (25 * USECS_PER_SEC, 10.0),
+            (29 * USECS_PER_SEC, 4.0), // counter reset: 4.0 < 10.0
+        ];
+
+        // raw_delta = (4 + correction(10)) - 10 = 4; sampled_interval = 4s,
+        // avg_interval = 4s, extrapolation_threshold = 2s; to_start = 5s
+        // capped to 2s, to_end = 1s (under the cap); extrapolated_interval =
+        // 4 + 2 + 1 = 7s, so increase = 4 * (7/4) = 7.0.
+        let actual = collect_deltas(&mut build(&points, greatest_time, 10, 10));
+        assert_eq!(actual, vec![Some(7.0)]);
+    }
+
+    #[test]
+    fn single_sample_windows_are_null() {
+        let greatest_time = 20 * USECS_PER_SEC;
+        let points = vec![(15 * USECS_PER_SEC, 1.0)];
+        let actual = collect_deltas(&mut build(&points, greatest_time, 10, 10));
+        assert_eq!(actual, vec![None]);
+    }
+
+    #[test]
+    fn max_buffered_duration_evicts_points_older_than_the_newest_seen() {
+        let greatest_time = 100 * USECS_PER_SEC;
+        let mut state = GapfillRateTransition::new(10, greatest_time, 10, 10, Some(5));
+
+        state.add_data_point(90 * USECS_PER_SEC, 1.0);
+        state.add_data_point(80 * USECS_PER_SEC, 2.0); // 10s behind the newest point: evicted
+        assert_eq!(state.window.len(), 1);
+
+        state.add_data_point(88 * USECS_PER_SEC, 3.0); // 2s behind the newest point: kept
+        assert_eq!(state.window.len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod gapfill_quantile_tests {
+    use super::*;
+
+    fn build(
+        points: &[(TimestampTz, f64)],
+        greatest_time: TimestampTz,
+        window_size: Seconds,
+        step_size: Seconds,
+        quantile: f64,
+    ) -> GapfillQuantileTransition {
+        let mut state = GapfillQuantileTransition::new(
+            points.len(), greatest_time, window_size, step_size, quantile, None,
+        );
+        for &(time, val) in points {
+            state.add_data_point(time, val);
+        }
+        state
+    }
+
+    fn collect_quantiles(state: &mut GapfillQuantileTransition) -> Vec<Option<f64>> {
+        state.flush_all();
+        let mut deltas = state.deltas.iter();
+        state.nulls.iter()
+            .map(|&is_null| if is_null { None } else { Some(datum_as_f64(*deltas.next().unwrap())) })
+            .collect()
+    }
+
+    #[test]
+    fn single_value_windows_return_that_value() {
+        let greatest_time = 30 * USECS_PER_SEC;
+        let points = vec![
+            (25 * USECS_PER_SEC, 100.0),
+            (5 * USECS_PER_SEC, 200.0),
+        ];
+
+        // each bucket holds exactly one sample, so the p50 histogram read-out
+        // should land within the HDR histogram's significant-figure precision
+        // of the recorded value itself.
+        let actual = collect_quantiles(&mut build(&points, greatest_time, 10, 10, 0.5));
+        assert_eq!(actual.len(), 3);
+
+        let bucket0 = actual[0].expect("bucket has a sample");
+        assert!((bucket0 - 100.0).abs() < 1.0, "expected ~100.0, got {}", bucket0);
+
+        assert_eq!(actual[1], None);
+
+        let bucket2 = actual[2].expect("bucket has a sample");
+        assert!((bucket2 - 200.0).abs() < 1.0, "expected ~200.0, got {}", bucket2);
+    }
+
+    #[test]
+    fn median_of_a_known_distribution() {
+        let greatest_time = 10 * USECS_PER_SEC;
+        let points = vec![
+            (1 * USECS_PER_SEC, 1.0),
+            (3 * USECS_PER_SEC, 2.0),
+            (5 * USECS_PER_SEC, 3.0),
+            (7 * USECS_PER_SEC, 4.0),
+            (9 * USECS_PER_SEC, 5.0),
+        ];
+
+        // the median of [1, 2, 3, 4, 5] is 3.0
+        let actual = collect_quantiles(&mut build(&points, greatest_time, 10, 10, 0.5));
+        assert_eq!(actual.len(), 1);
+        let median = actual[0].expect("bucket has samples");
+        assert!((median - 3.0).abs() < 0.01, "expected ~3.0, got {}", median);
+    }
+
+    #[test]
+    fn max_buffered_duration_evicts_points_older_than_the_newest_seen() {
+        let greatest_time = 100 * USECS_PER_SEC;
+        let mut state = GapfillQuantileTransition::new(10, greatest_time, 10, 10, 0.5, Some(5));
+
+        state.add_data_point(90 * USECS_PER_SEC, 1.0);
+        state.add_data_point(80 * USECS_PER_SEC, 2.0); // 10s behind the newest point: evicted
+        assert_eq!(state.points.len(), 1);
+
+        state.add_data_point(88 * USECS_PER_SEC, 3.0); // 2s behind the newest point: kept
+        assert_eq!(state.points.len(), 2);
+    }
+}